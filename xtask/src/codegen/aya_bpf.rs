@@ -1,88 +1,458 @@
-use std::{fs::File, io::Write, path::PathBuf, process::Command};
+use std::{collections::HashMap, fs, fs::File, io::Write, path::PathBuf, process::Command};
 
 use anyhow::anyhow;
+use bindgen::callbacks::{IntKind, ParseCallbacks};
 use proc_macro2::TokenStream;
 use quote::{quote, ToTokens};
+use serde::Deserialize;
 use structopt::StructOpt;
 use syn::{
     self, parse_str,
     punctuated::Punctuated,
     token::Comma,
+    visit::{self, Visit},
     visit_mut::{self, VisitMut},
-    AngleBracketedGenericArguments, ForeignItemStatic, GenericArgument, Ident, Item,
-    PathArguments::AngleBracketed,
-    Type,
+    AngleBracketedGenericArguments, Expr, ForeignItemStatic, GenericArgument, Ident, Item,
+    ItemConst, ItemForeignMod, Lit, PathArguments::AngleBracketed,
+    Type, UnOp,
 };
 
 #[derive(StructOpt)]
 pub struct CodegenOptions {
     #[structopt(long)]
     libbpf_dir: PathBuf,
+    /// Generate bindings from a kernel's BTF (e.g. `/sys/kernel/btf/vmlinux`)
+    /// instead of the checked-in `aya_bpf_bindings.h` header, for CO-RE builds.
+    #[structopt(long)]
+    btf: Option<PathBuf>,
+    /// A TOML manifest describing one or more modules to generate; when given,
+    /// this replaces the hard-coded `bindings.rs`/`helpers.rs` whitelist logic.
+    #[structopt(long)]
+    config: Option<PathBuf>,
+    /// A target triple to generate architecture-specific bindings for (e.g.
+    /// `x86_64-unknown-linux-gnu`); may be repeated to generate bindings for
+    /// several architectures in one invocation.
+    #[structopt(long = "target")]
+    targets: Vec<String>,
+}
+
+/// The leading component of a target triple, used as the namespaced output
+/// directory and `cfg` arm; clang itself is given the full triple.
+fn target_arch(target: &str) -> &str {
+    target.split('-').next().unwrap_or(target)
+}
+
+/// One `[[module]]` entry in a `--config` manifest: a whitelist/blacklist of
+/// types and vars to pull out of `header`, written to `output`.
+#[derive(Deserialize)]
+struct ModuleConfig {
+    header: PathBuf,
+    output: PathBuf,
+    #[serde(default)]
+    whitelist_types: Vec<String>,
+    #[serde(default)]
+    whitelist_vars: Vec<String>,
+    #[serde(default)]
+    blacklist_types: Vec<String>,
+    #[serde(default)]
+    blacklist_vars: Vec<String>,
+    #[serde(default)]
+    rewrite_helpers: bool,
+}
+
+#[derive(Deserialize)]
+struct Manifest {
+    module: Vec<ModuleConfig>,
+}
+
+impl Manifest {
+    /// Rejects manifests whose `--config`/`--btf` combination can't do what
+    /// it looks like it asks for, instead of silently discarding per-module
+    /// headers the manifest author chose on purpose.
+    fn validate(&self, btf: Option<&PathBuf>) -> Result<(), anyhow::Error> {
+        if btf.is_some() {
+            let mut headers = self.module.iter().map(|m| &m.header);
+            if let Some(first) = headers.next() {
+                if headers.any(|h| h != first) {
+                    return Err(anyhow!(
+                        "--btf overrides every module's `header` with the same BTF-derived \
+                         file, but this manifest's modules declare different `header` values; \
+                         drop --btf or give every module the same header"
+                    ));
+                }
+            }
+        }
+
+        for module in &self.module {
+            if module.rewrite_helpers
+                && !module.whitelist_vars.iter().any(|v| v.contains("BPF_FUNC"))
+            {
+                return Err(anyhow!(
+                    "module `{}` sets rewrite_helpers = true but its whitelist_vars doesn't \
+                     include a BPF_FUNC_.* entry, so no call-index constants would be collected \
+                     and every helper would fail to resolve; add a whitelist_var matching \
+                     `BPF_FUNC_.*`",
+                    module.output.display()
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Dumps the C type/helper layout encoded in a kernel's BTF into a header
+/// that bindgen can consume, so generated bindings track the running
+/// kernel instead of a checked-in snapshot. Every `struct`/`union` in the
+/// dump is wrapped in `__attribute__((preserve_access_index))`, the same
+/// marker libbpf's own `vmlinux.h` relies on. `bindgen`/libclang only reads
+/// this header for its type *shapes*, so that marker doesn't itself give the
+/// Rust bindings CO-RE relocations; it primes the header so a future C/clang
+/// consumer of it (or a relocation pass added on top of these bindings) gets
+/// real BTF relocations for free instead of having to rewrap the dump later.
+fn btf_to_header(btf: &PathBuf, dest: &PathBuf) -> Result<(), anyhow::Error> {
+    let output = Command::new("bpftool")
+        .arg("btf")
+        .arg("dump")
+        .arg("file")
+        .arg(btf)
+        .arg("format")
+        .arg("c")
+        .output()?;
+
+    if !output.status.success() {
+        eprintln!("{}", std::str::from_utf8(&output.stderr)?);
+        return Err(anyhow!("bpftool btf dump failed: {}", output.status));
+    }
+
+    let dump = std::str::from_utf8(&output.stdout)?;
+
+    let mut file = File::create(dest)?;
+    writeln!(
+        file,
+        "#pragma clang attribute push (__attribute__((preserve_access_index)), apply_to = record)"
+    )?;
+    file.write_all(dump.as_bytes())?;
+    writeln!(file, "#pragma clang attribute pop")?;
+
+    Ok(())
+}
+
+#[derive(Debug)]
+struct BpfBindgenCallbacks;
+
+impl ParseCallbacks for BpfBindgenCallbacks {
+    fn int_macro(&self, name: &str, _value: i64) -> Option<IntKind> {
+        if name.starts_with("BPF_") {
+            Some(IntKind::Custom {
+                name: "i64",
+                is_signed: true,
+            })
+        } else {
+            None
+        }
+    }
 }
 
 pub fn codegen(opts: CodegenOptions) -> Result<(), anyhow::Error> {
     let dir = PathBuf::from("bpf/aya-bpf");
     let generated = dir.join("src/bpf/generated");
 
-    let types: Vec<&str> = vec!["bpf_map_.*"];
-    let vars = vec!["BPF_.*", "bpf_.*"];
-    let mut cmd = Command::new("bindgen");
-    cmd.arg("--no-layout-tests")
-        .arg("--use-core")
-        .arg("--ctypes-prefix")
-        .arg("::aya_bpf_cty")
-        .arg("--default-enum-style")
-        .arg("consts")
-        .arg("--no-prepend-enum-name")
-        .arg(&*dir.join("include/aya_bpf_bindings.h").to_string_lossy());
+    if opts.targets.is_empty() {
+        generate_all(&opts, &dir, &generated, None)?;
+        return Ok(());
+    }
+
+    for target in &opts.targets {
+        let arch = target_arch(target);
+        let arch_dir = generated.join(arch);
+        let modules = generate_all(&opts, &dir, &arch_dir, Some(target))?;
 
-    for x in types {
-        cmd.arg("--whitelist-type").arg(x);
+        let filename = arch_dir.join("mod.rs");
+        {
+            let mut file = File::create(&filename)?;
+            for module in &modules {
+                writeln!(file, "pub mod {module};")?;
+            }
+        }
+        Command::new("rustfmt").arg(filename).status()?;
     }
 
-    for x in vars {
-        cmd.arg("--whitelist-var").arg(x);
+    fs::create_dir_all(&generated)?;
+    let filename = generated.join("mod.rs");
+    {
+        let mut file = File::create(&filename)?;
+        for target in &opts.targets {
+            let arch = target_arch(target);
+            writeln!(file, "#[cfg(target_arch = \"{arch}\")]")?;
+            writeln!(file, "mod {arch};")?;
+            writeln!(file, "#[cfg(target_arch = \"{arch}\")]")?;
+            writeln!(file, "pub use {arch}::*;")?;
+        }
     }
+    Command::new("rustfmt").arg(filename).status()?;
 
-    cmd.arg("--");
-    cmd.arg("-I").arg(opts.libbpf_dir.join("src"));
+    Ok(())
+}
 
-    let output = cmd.output()?;
-    let bindings = std::str::from_utf8(&output.stdout)?;
+/// Runs the config-driven (or default single-module) binding generation for
+/// one target, writing its output under `generated`. `target`, when set, is
+/// passed to clang so the generated types reflect that architecture's ABI.
+/// Returns the stem (module name, minus `.rs`) of every file written, so
+/// callers can declare them from a `mod.rs`.
+fn generate_all(
+    opts: &CodegenOptions,
+    dir: &PathBuf,
+    generated: &PathBuf,
+    target: Option<&str>,
+) -> Result<Vec<String>, anyhow::Error> {
+    let mut modules = Vec::new();
 
-    if !output.status.success() {
-        eprintln!("{}", std::str::from_utf8(&output.stderr)?);
-        return Err(anyhow!("bindgen failed: {}", output.status));
+    match &opts.config {
+        Some(config) => {
+            let manifest: Manifest = toml::from_str(&fs::read_to_string(config)?)?;
+            manifest.validate(opts.btf.as_ref())?;
+            for module in &manifest.module {
+                modules.extend(generate_module(
+                    opts,
+                    &module.header,
+                    &module.whitelist_types,
+                    &module.whitelist_vars,
+                    &module.blacklist_types,
+                    &module.blacklist_vars,
+                    module.rewrite_helpers,
+                    &generated.join(&module.output),
+                    target,
+                )?);
+            }
+        }
+        None => {
+            let header = dir.join("include/aya_bpf_bindings.h");
+            modules.extend(generate_module(
+                opts,
+                &header,
+                &["bpf_map_.*".to_owned()],
+                &["BPF_.*".to_owned(), "bpf_.*".to_owned()],
+                &[],
+                &[],
+                true,
+                &generated.join("bindings.rs"),
+                target,
+            )?);
+        }
+    }
+
+    Ok(modules)
+}
+
+/// Generates a single module of bindings: runs bindgen over `header` (or, with
+/// `--btf`, over a `vmlinux.h` dumped beside `output`) with the given
+/// whitelist/blacklist regex sets, optionally splits out helper wrappers via
+/// [`RewriteBpfHelpers`], and rustfmts the bindings into `output` and the
+/// helper wrappers into a `helpers.rs` beside it. When `target` is set, clang
+/// generates types for that architecture instead of the host's.
+fn generate_module(
+    opts: &CodegenOptions,
+    header: &PathBuf,
+    whitelist_types: &[String],
+    whitelist_vars: &[String],
+    blacklist_types: &[String],
+    blacklist_vars: &[String],
+    rewrite_helpers: bool,
+    output: &PathBuf,
+    target: Option<&str>,
+) -> Result<Vec<String>, anyhow::Error> {
+    if let Some(parent) = output.parent() {
+        fs::create_dir_all(parent)?;
     }
 
-    // delete the helpers, then rewrite them in helpers.rs
-    let mut tree = parse_str::<syn::File>(bindings).unwrap();
-    let mut tx = RewriteBpfHelpers {
-        helpers: Vec::new(),
+    let header = match &opts.btf {
+        Some(btf) => {
+            let vmlinux = output
+                .parent()
+                .unwrap_or_else(|| std::path::Path::new("."))
+                .join("vmlinux.h");
+            btf_to_header(btf, &vmlinux)?;
+            vmlinux
+        }
+        None => header.clone(),
     };
-    tx.visit_file_mut(&mut tree);
 
-    let filename = generated.join("bindings.rs");
+    let mut builder = bindgen::Builder::default()
+        .header(header.to_string_lossy())
+        .layout_tests(false)
+        .use_core()
+        .ctypes_prefix("::aya_bpf_cty")
+        .default_enum_style(bindgen::EnumVariation::Consts)
+        .prepend_enum_name(false)
+        .parse_callbacks(Box::new(BpfBindgenCallbacks))
+        .clang_arg("-I")
+        .clang_arg(opts.libbpf_dir.join("src").to_string_lossy());
+
+    if let Some(target) = target {
+        builder = builder.clang_arg(format!("--target={}", target));
+    }
+
+    for x in whitelist_types {
+        builder = builder.whitelist_type(x);
+    }
+    for x in whitelist_vars {
+        builder = builder.whitelist_var(x);
+    }
+    for x in blacklist_types {
+        builder = builder.blacklist_type(x);
+    }
+    for x in blacklist_vars {
+        builder = builder.blacklist_item(x);
+    }
+
+    let bindings = builder
+        .generate()
+        .map_err(|_| anyhow!("bindgen failed"))?
+        .to_string();
+
+    let mut tree = parse_str::<syn::File>(&bindings).unwrap();
+
+    let helpers = if rewrite_helpers {
+        // bindgen emits the kernel's `enum bpf_func_id` as `BPF_FUNC_<name>`
+        // integer constants; collect them so helper call numbers can be looked
+        // up by name instead of assumed from item order.
+        let mut call_indices = CollectCallIndices::default();
+        call_indices.visit_file(&tree);
+
+        // delete the helpers, then rewrite them alongside bindings
+        let mut tx = RewriteBpfHelpers {
+            call_indices: call_indices.indices,
+            helpers: Vec::new(),
+        };
+        tx.visit_file_mut(&mut tree);
+        tx.helpers
+    } else {
+        Vec::new()
+    };
+
+    // binding generation doesn't guarantee stable item ordering, so regenerating
+    // can otherwise produce large spurious diffs; merge and sort before writing
+    // so unrelated regenerations yield minimal, reviewable diffs.
+    normalize_item_order(&mut tree);
+
     {
-        let mut file = File::create(&filename)?;
+        let mut file = File::create(output)?;
         write!(file, "{}", tree.to_token_stream())?;
     }
-    Command::new("rustfmt").arg(filename).status()?;
+    Command::new("rustfmt").arg(output).status()?;
 
-    let filename = generated.join("helpers.rs");
-    {
-        let mut file = File::create(&filename)?;
-        write!(file, "use crate::bpf::generated::bindings::*;")?;
-        for helper in &tx.helpers {
-            file.write(helper.as_bytes())?;
+    let mut modules = vec![output
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or("bindings")
+        .to_owned()];
+
+    if rewrite_helpers {
+        let filename = output
+            .parent()
+            .unwrap_or_else(|| std::path::Path::new("."))
+            .join("helpers.rs");
+        {
+            let mut file = File::create(&filename)?;
+            write!(file, "use crate::bpf::generated::bindings::*;")?;
+            for helper in &helpers {
+                file.write(helper.as_bytes())?;
+            }
         }
+        Command::new("rustfmt").arg(filename).status()?;
+        modules.push("helpers".to_owned());
     }
-    Command::new("rustfmt").arg(filename).status()?;
 
-    Ok(())
+    Ok(modules)
+}
+
+#[derive(Default)]
+struct CollectCallIndices {
+    indices: HashMap<String, i64>,
+}
+
+impl<'ast> Visit<'ast> for CollectCallIndices {
+    fn visit_item_const(&mut self, item: &'ast ItemConst) {
+        visit::visit_item_const(self, item);
+        let name = item.ident.to_string();
+        if !name.starts_with("BPF_FUNC_") {
+            return;
+        }
+        if let Expr::Lit(expr) = &*item.expr {
+            if let Lit::Int(lit) = &expr.lit {
+                if let Ok(value) = lit.base10_parse::<i64>() {
+                    self.indices.insert(name, value);
+                }
+            }
+        } else if let Expr::Unary(expr) = &*item.expr {
+            if let (UnOp::Neg(_), Expr::Lit(lit)) = (&expr.op, &*expr.expr) {
+                if let Lit::Int(lit) = &lit.lit {
+                    if let Ok(value) = lit.base10_parse::<i64>() {
+                        self.indices.insert(name, -value);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Merges all remaining `extern` blocks into one and sorts top-level items by
+/// kind and name, so that unrelated regenerations yield minimal diffs.
+fn normalize_item_order(tree: &mut syn::File) {
+    merge_extern_blocks(&mut tree.items);
+    tree.items.sort_by_key(semantic_sort_key);
+}
+
+fn merge_extern_blocks(items: &mut Vec<Item>) {
+    let mut merged: Option<ItemForeignMod> = None;
+    let mut merged_at = None;
+    let mut i = 0;
+    while i < items.len() {
+        if let Item::ForeignMod(_) = &items[i] {
+            match merged.as_mut() {
+                None => {
+                    merged_at = Some(i);
+                    merged = Some(match items[i].clone() {
+                        Item::ForeignMod(fm) => fm,
+                        _ => unreachable!(),
+                    });
+                    i += 1;
+                }
+                Some(merged) => {
+                    let fm = match items.remove(i) {
+                        Item::ForeignMod(fm) => fm,
+                        _ => unreachable!(),
+                    };
+                    merged.items.extend(fm.items);
+                }
+            }
+        } else {
+            i += 1;
+        }
+    }
+    if let (Some(merged), Some(at)) = (merged, merged_at) {
+        items[at] = Item::ForeignMod(merged);
+    }
+}
+
+/// Groups items by kind (types, then constants, then statics, then
+/// everything else) and alphabetizes types, constants and statics by name;
+/// items in the last group keep their relative order.
+fn semantic_sort_key(item: &Item) -> (u8, String) {
+    match item {
+        Item::Type(i) => (0, i.ident.to_string()),
+        Item::Struct(i) => (0, i.ident.to_string()),
+        Item::Enum(i) => (0, i.ident.to_string()),
+        Item::Union(i) => (0, i.ident.to_string()),
+        Item::Const(i) => (1, i.ident.to_string()),
+        Item::Static(i) => (2, i.ident.to_string()),
+        _ => (3, String::new()),
+    }
 }
 
 struct RewriteBpfHelpers {
+    call_indices: HashMap<String, i64>,
     helpers: Vec<String>,
 }
 
@@ -112,7 +482,14 @@ impl VisitMut for RewriteBpfHelpers {
                 }
                 .to_string();
                 ty_s = ty_s.replace("fn (", &format!("fn {} (", ident_str));
-                let call_idx = self.helpers.len() + 1;
+                let suffix = ident_str.trim_start_matches("bpf_");
+                let const_name = format!("BPF_FUNC_{}", suffix);
+                let call_idx = *self.call_indices.get(&const_name).unwrap_or_else(|| {
+                    panic!(
+                        "no {} constant found for helper {}; bindgen output no longer matches the kernel's enum bpf_func_id",
+                        const_name, ident_str
+                    )
+                });
                 let args: Punctuated<Ident, Comma> = match fn_ty {
                     GenericArgument::Type(Type::BareFn(f)) => f
                         .inputs
@@ -137,4 +514,115 @@ impl VisitMut for RewriteBpfHelpers {
             }
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn collect_call_indices_resolves_positive_and_negative_constants() {
+        let tree = parse_str::<syn::File>(
+            r#"
+                pub const BPF_FUNC_trace_printk: i64 = 6i64;
+                pub const BPF_FUNC_map_lookup_elem: i64 = -1i64;
+                pub const OTHER_CONST: i64 = 9i64;
+            "#,
+        )
+        .unwrap();
+
+        let mut call_indices = CollectCallIndices::default();
+        call_indices.visit_file(&tree);
+
+        assert_eq!(call_indices.indices.get("BPF_FUNC_trace_printk"), Some(&6));
+        assert_eq!(call_indices.indices.get("BPF_FUNC_map_lookup_elem"), Some(&-1));
+        assert_eq!(call_indices.indices.get("OTHER_CONST"), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "no BPF_FUNC_trace_printk constant found")]
+    fn rewrite_bpf_helpers_fails_loudly_on_missing_constant() {
+        let mut tree = parse_str::<syn::File>(
+            r#"
+                extern "C" {
+                    pub static mut bpf_trace_printk: ::core::option::Option<
+                        unsafe extern "C" fn(a: u64) -> i64,
+                    >;
+                }
+            "#,
+        )
+        .unwrap();
+
+        let mut tx = RewriteBpfHelpers {
+            call_indices: HashMap::new(),
+            helpers: Vec::new(),
+        };
+        tx.visit_file_mut(&mut tree);
+    }
+
+    #[test]
+    fn merge_extern_blocks_combines_in_place_at_first_blocks_position() {
+        let mut tree = parse_str::<syn::File>(
+            r#"
+                pub const A: i64 = 1i64;
+                extern "C" {
+                    pub static mut first: i64;
+                }
+                pub const B: i64 = 2i64;
+                extern "C" {
+                    pub static mut second: i64;
+                }
+            "#,
+        )
+        .unwrap();
+
+        merge_extern_blocks(&mut tree.items);
+
+        let foreign_mods: Vec<usize> = tree
+            .items
+            .iter()
+            .enumerate()
+            .filter(|(_, item)| matches!(item, Item::ForeignMod(_)))
+            .map(|(i, _)| i)
+            .collect();
+        assert_eq!(foreign_mods, vec![1]);
+
+        let merged = match &tree.items[1] {
+            Item::ForeignMod(fm) => fm,
+            _ => unreachable!(),
+        };
+        assert_eq!(merged.items.len(), 2);
+    }
+
+    #[test]
+    fn semantic_sort_key_groups_by_kind_then_alphabetizes() {
+        let tree = parse_str::<syn::File>(
+            r#"
+                pub static mut z_static: i64 = 0i64;
+                pub const b_const: i64 = 1i64;
+                pub struct AStruct;
+                pub const a_const: i64 = 2i64;
+                pub struct BStruct;
+            "#,
+        )
+        .unwrap();
+
+        let mut items = tree.items;
+        items.sort_by_key(semantic_sort_key);
+
+        let names: Vec<String> = items
+            .iter()
+            .map(|item| match item {
+                Item::Struct(i) => i.ident.to_string(),
+                Item::Const(i) => i.ident.to_string(),
+                Item::Static(i) => i.ident.to_string(),
+                _ => unreachable!(),
+            })
+            .collect();
+
+        assert_eq!(
+            names,
+            vec!["AStruct", "BStruct", "a_const", "b_const", "z_static"]
+        );
+    }
+}